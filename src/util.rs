@@ -17,7 +17,7 @@ use std::os::windows::ffi;
 #[cfg(windows)]
 use std::os::windows::io::{AsRawHandle, RawHandle};
 use std::str::{self, FromStr};
-use std::sync::mpsc::{channel, Sender};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
 
 use bstr::ByteSlice;
 use crossbeam::thread::{Scope, ScopedJoinHandle};
@@ -41,6 +41,7 @@ macro_rules! derive_debug_display {
 pub struct PrefixWriter<W: Write> {
     prefix: Vec<u8>,
     line_writer: LineWriter<W>,
+    at_line_start: bool,
 }
 
 impl<W: Write> PrefixWriter<W> {
@@ -48,6 +49,7 @@ impl<W: Write> PrefixWriter<W> {
         PrefixWriter {
             prefix: prefix.to_owned(),
             line_writer: LineWriter::new(w),
+            at_line_start: true,
         }
     }
 }
@@ -56,7 +58,10 @@ impl<W: Write> Write for PrefixWriter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let mut len = 0;
         for line in buf.lines_with_terminator() {
-            self.line_writer.write_all(&self.prefix)?;
+            if self.at_line_start {
+                self.line_writer.write_all(&self.prefix)?;
+            }
+            self.at_line_start = line.last() == Some(&b'\n');
             len += self.line_writer.write(line)?;
         }
         Ok(len)
@@ -67,17 +72,46 @@ impl<W: Write> Write for PrefixWriter<W> {
     }
 }
 
+#[test]
+fn test_prefix_writer_split_across_writes() {
+    let mut out = Vec::new();
+    {
+        let mut writer = PrefixWriter::new(b"> ", &mut out);
+        writer.write_all(b"foo").unwrap();
+        writer.write_all(b"bar\nbaz").unwrap();
+        writer.write_all(b"\n").unwrap();
+        writer.write_all(b"qux\n").unwrap();
+    }
+    assert_eq!(&out[..], &b"> foobar\n> baz\n> qux\n"[..]);
+}
+
+#[test]
+fn test_prefix_writer_split_across_three_writes() {
+    let mut out = Vec::new();
+    {
+        let mut writer = PrefixWriter::new(b"$ ", &mut out);
+        writer.write_all(b"a").unwrap();
+        writer.write_all(b"b").unwrap();
+        writer.write_all(b"c\n").unwrap();
+    }
+    assert_eq!(&out[..], &b"$ abc\n"[..]);
+}
+
 pub struct BufferedWriter<'scope> {
     thread: Option<ScopedJoinHandle<'scope, io::Result<()>>>,
     sender: Option<Sender<Vec<u8>>>,
+    recycled: Receiver<Vec<u8>>,
 }
 
 impl<'scope> BufferedWriter<'scope> {
     pub fn new<'a: 'scope, W: 'a + Write + Send>(mut w: W, scope: &'scope Scope<'a>) -> Self {
         let (sender, receiver) = channel::<Vec<u8>>();
+        let (recycle_sender, recycled) = channel::<Vec<u8>>();
         let thread = scope.spawn(move |_| {
-            for buf in receiver.iter() {
+            for mut buf in receiver.iter() {
                 w.write_all(&buf)?;
+                buf.clear();
+                let _ = recycle_sender.send(buf);
             }
             w.flush()?;
             Ok(())
@@ -85,6 +119,7 @@ impl<'scope> BufferedWriter<'scope> {
         BufferedWriter {
             thread: Some(thread),
             sender: Some(sender),
+            recycled,
         }
     }
 }
@@ -98,7 +133,11 @@ impl<'scope> Drop for BufferedWriter<'scope> {
 
 impl<'scope> Write for BufferedWriter<'scope> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.sender.as_ref().map(|s| s.send(buf.to_owned()));
+        let mut owned = self.recycled.try_recv().unwrap_or_default();
+        owned.clear();
+        owned.reserve(buf.len());
+        owned.extend_from_slice(buf);
+        self.sender.as_ref().map(|s| s.send(owned));
         Ok(buf.len())
     }
 
@@ -147,6 +186,120 @@ fn test_buffered_writer() {
     .unwrap();
 }
 
+const BUFFERED_READER_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct BufferedReader<'scope> {
+    thread: Option<ScopedJoinHandle<'scope, ()>>,
+    receiver: Option<Receiver<io::Result<Vec<u8>>>>,
+    pending: Option<(Vec<u8>, usize)>,
+    eof: bool,
+}
+
+impl<'scope> BufferedReader<'scope> {
+    pub fn new<'a: 'scope, R: 'a + Read + Send>(
+        mut r: R,
+        chunks: usize,
+        scope: &'scope Scope<'a>,
+    ) -> Self {
+        let (sender, receiver) = sync_channel::<io::Result<Vec<u8>>>(chunks);
+        let thread = scope.spawn(move |_| loop {
+            let mut buf = vec![0u8; BUFFERED_READER_CHUNK_SIZE];
+            match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => {
+                    buf.truncate(len);
+                    if sender.send(Ok(buf)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        });
+        BufferedReader {
+            thread: Some(thread),
+            receiver: Some(receiver),
+            pending: None,
+            eof: false,
+        }
+    }
+}
+
+impl<'scope> Drop for BufferedReader<'scope> {
+    fn drop(&mut self) {
+        // Drop the receiver first so the reader thread, which may be
+        // blocked sending (the channel is bounded), unblocks with a
+        // disconnected-channel error and exits on its own, instead of
+        // racing a one-shot drain against the thread refilling the
+        // channel.
+        drop(self.receiver.take());
+        self.thread.take().unwrap().join().unwrap();
+    }
+}
+
+impl<'scope> Read for BufferedReader<'scope> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+        let (chunk, pos) = match self.pending.take() {
+            Some(p) => p,
+            None => match self.receiver.as_ref().unwrap().recv() {
+                Ok(Ok(chunk)) => (chunk, 0),
+                Ok(Err(e)) => {
+                    self.eof = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.eof = true;
+                    return Ok(0);
+                }
+            },
+        };
+        let available = &chunk[pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        if pos + len < chunk.len() {
+            self.pending = Some((chunk, pos + len));
+        }
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_buffered_reader() {
+    use crossbeam::thread;
+    use std::io::Read as _;
+    use std::time::{Duration, Instant};
+
+    struct SlowRead<'a>(&'a [u8]);
+
+    impl<'a> Read for SlowRead<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.read(buf)
+        }
+    }
+
+    let data = vec![42u8; 20];
+    thread::scope(|s| {
+        let mut reader = BufferedReader::new(SlowRead(&data), 4, s);
+        // Give the reader thread a head start filling the channel.
+        std::thread::sleep(Duration::from_millis(5));
+        let start_time = Instant::now();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        let read_time = Instant::now();
+        assert_eq!(out, data);
+        // By the time we start reading, the background thread should
+        // already have buffered (some of) the data.
+        assert_lt!((read_time - start_time).as_micros(), 5000);
+    })
+    .unwrap();
+}
+
 pub trait ReadExt: Read {
     fn read_at_most(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let mut input = self.take(buf.len().try_into().unwrap());
@@ -509,3 +662,215 @@ impl<T: AsRawHandle> Duplicate for T {
         DuplicateFd(handle)
     }
 }
+
+#[cfg(target_os = "linux")]
+const COPY_FD_CHUNK_SIZE: usize = 64 * 1024;
+
+// Like std::io::copy, but on Linux attempts a zero-copy kernel-offloaded
+// transfer first (sendfile(2), or splice(2) through an intermediate pipe
+// when src isn't a regular file), falling back to std::io::copy on
+// EINVAL/ENOSYS/EXDEV (and unconditionally on non-Linux platforms).
+#[cfg(target_os = "linux")]
+pub fn copy_fd<R: AsRawFd + Read, W: AsRawFd + Write>(src: &mut R, dst: &mut W) -> io::Result<u64> {
+    let src_is_regular = unsafe {
+        let mut stat = MaybeUninit::<libc::stat>::uninit();
+        libc::fstat(src.as_raw_fd(), stat.as_mut_ptr()) == 0
+            && (stat.assume_init().st_mode & libc::S_IFMT) == libc::S_IFREG
+    };
+    let result = if src_is_regular {
+        copy_fd_sendfile(src.as_raw_fd(), dst.as_raw_fd())
+    } else {
+        copy_fd_splice(src.as_raw_fd(), dst.as_raw_fd())
+    };
+    match result {
+        Ok(copied) => Ok(copied),
+        // `copied` is however many bytes sendfile/splice already
+        // transferred before hitting the error; the fd position has
+        // advanced past them, so the std::io::copy fallback picks up
+        // from there and its result is added on top, not used alone.
+        Err((copied, e))
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EXDEV)
+            ) =>
+        {
+            Ok(copied + copy(src, dst)?)
+        }
+        Err((_, e)) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn copy_fd_sendfile(src_fd: RawFd, dst_fd: RawFd) -> Result<u64, (u64, io::Error)> {
+    let mut copied = 0u64;
+    loop {
+        let ret =
+            unsafe { libc::sendfile(dst_fd, src_fd, std::ptr::null_mut(), COPY_FD_CHUNK_SIZE) };
+        match ret {
+            0 => break,
+            n if n > 0 => copied += n as u64,
+            _ => return Err((copied, io::Error::last_os_error())),
+        }
+    }
+    Ok(copied)
+}
+
+// Forwards up to `remaining` bytes already sitting in `pipe_read` to
+// `dst_fd` via plain read()/write(), rather than splice(), since this is
+// used to recover bytes stranded in the pipe after dst_fd rejected a
+// splice() write. Best-effort: stops and returns what it managed to
+// forward if a read or write fails.
+#[cfg(target_os = "linux")]
+fn drain_pipe_to_fd(pipe_read: RawFd, dst_fd: RawFd, mut remaining: usize) -> u64 {
+    let mut forwarded = 0u64;
+    let mut buf = vec![0u8; COPY_FD_CHUNK_SIZE];
+    while remaining > 0 {
+        let n = unsafe {
+            libc::read(
+                pipe_read,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                remaining.min(buf.len()),
+            )
+        };
+        if n <= 0 {
+            break;
+        }
+        let mut written = 0usize;
+        while written < n as usize {
+            let w = unsafe {
+                libc::write(
+                    dst_fd,
+                    buf[written..n as usize].as_ptr() as *const libc::c_void,
+                    n as usize - written,
+                )
+            };
+            if w <= 0 {
+                return forwarded;
+            }
+            written += w as usize;
+            forwarded += w as u64;
+        }
+        remaining -= n as usize;
+    }
+    forwarded
+}
+
+#[cfg(target_os = "linux")]
+fn copy_fd_splice(src_fd: RawFd, dst_fd: RawFd) -> Result<u64, (u64, io::Error)> {
+    let mut pipe_fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe2(pipe_fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err((0, io::Error::last_os_error()));
+    }
+    let (pipe_read, pipe_write) = (pipe_fds[0], pipe_fds[1]);
+    let result = (|| {
+        let mut copied = 0u64;
+        loop {
+            let n = unsafe {
+                libc::splice(
+                    src_fd,
+                    std::ptr::null_mut(),
+                    pipe_write,
+                    std::ptr::null_mut(),
+                    COPY_FD_CHUNK_SIZE,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if n < 0 {
+                return Err((copied, io::Error::last_os_error()));
+            }
+            if n == 0 {
+                break;
+            }
+            let mut remaining = n as usize;
+            while remaining > 0 {
+                let written = unsafe {
+                    libc::splice(
+                        pipe_read,
+                        std::ptr::null_mut(),
+                        dst_fd,
+                        std::ptr::null_mut(),
+                        remaining,
+                        libc::SPLICE_F_MOVE,
+                    )
+                };
+                if written <= 0 {
+                    let err = io::Error::last_os_error();
+                    // The failed splice() didn't consume anything, so
+                    // `remaining` bytes are still sitting in the pipe;
+                    // forward them now instead of stranding them when
+                    // the pipe is closed below.
+                    copied += drain_pipe_to_fd(pipe_read, dst_fd, remaining);
+                    return Err((copied, err));
+                }
+                remaining -= written as usize;
+                copied += written as u64;
+            }
+        }
+        Ok(copied)
+    })();
+    unsafe {
+        libc::close(pipe_read);
+        libc::close(pipe_write);
+    }
+    result
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn copy_fd<R: AsRawFd + Read, W: AsRawFd + Write>(src: &mut R, dst: &mut W) -> io::Result<u64> {
+    copy(src, dst)
+}
+
+#[cfg(windows)]
+pub fn copy_fd<R: Read, W: Write>(src: &mut R, dst: &mut W) -> io::Result<u64> {
+    copy(src, dst)
+}
+
+// Raises the process' soft limit on the number of open file descriptors
+// to the platform's hard limit, never lowering it. Returns the effective
+// soft limit, or 0 if the current limit cannot be read (and always 0 on
+// non-unix, where this is a no-op).
+#[cfg(unix)]
+pub fn raise_fd_limit() -> u64 {
+    unsafe {
+        let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+        if libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) != 0 {
+            return 0;
+        }
+        let mut limit = limit.assume_init();
+
+        #[cfg(target_os = "macos")]
+        let target = {
+            let mut max_files_per_proc: libc::c_int = 0;
+            let mut size = mem::size_of_val(&max_files_per_proc);
+            let name = CString::new("kern.maxfilesperproc").unwrap();
+            let ret = libc::sysctlbyname(
+                name.as_ptr(),
+                &mut max_files_per_proc as *mut _ as *mut libc::c_void,
+                &mut size,
+                std::ptr::null_mut(),
+                0,
+            );
+            if ret == 0 {
+                limit.rlim_max.min(max_files_per_proc as libc::rlim_t)
+            } else {
+                limit.rlim_max
+            }
+        };
+        #[cfg(not(target_os = "macos"))]
+        let target = limit.rlim_max;
+
+        if target > limit.rlim_cur {
+            let mut new_limit = limit;
+            new_limit.rlim_cur = target;
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &new_limit) == 0 {
+                limit = new_limit;
+            }
+        }
+        limit.rlim_cur
+    }
+}
+
+#[cfg(not(unix))]
+pub fn raise_fd_limit() -> u64 {
+    0
+}